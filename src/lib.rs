@@ -18,29 +18,108 @@ extern crate cita_trie;
 extern crate rocksdb;
 
 use cita_trie::db::DB;
-use rocksdb::{Writable, DB as RDB};
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, ColumnFamilyOptions, DBOptions, MergeOperands,
+    Writable, WriteBatch, DB as RDB,
+};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
 use std::error;
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::hash::Hash;
+use std::mem;
 use std::sync::Arc;
 
-/// Wrapper for RocksDb errors that are all Strings
+/// Column family holding the per-node reference count used by GC.
+const REFCOUNT_CF: &str = "__node_refcounts";
+
+/// Sum `existing` (treating a missing base as zero) with every operand in
+/// `deltas`, each a little-endian encoded `i64`. Factored out of
+/// `refcount_merge` so the folding logic can be unit tested directly —
+/// `MergeOperands` itself is only ever constructed by rocksdb's own merge
+/// callback, not in test code.
+fn fold_refcount_deltas<'a>(existing: Option<&[u8]>, deltas: impl Iterator<Item = &'a [u8]>) -> i64 {
+    let mut count: i64 = existing
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(i64::from_le_bytes)
+        .unwrap_or(0);
+    for operand in deltas {
+        if let Ok(delta) = operand.try_into() {
+            count += i64::from_le_bytes(delta);
+        }
+    }
+    count
+}
+
+/// Associative (full) merge operator for `REFCOUNT_CF`. Each operand is a
+/// signed `i64` delta (little-endian bytes); folding sums the existing
+/// count with every pending delta via `fold_refcount_deltas`.
+fn refcount_merge(_key: &[u8], existing_val: Option<&[u8]>, operands: &mut MergeOperands) -> Vec<u8> {
+    fold_refcount_deltas(existing_val, operands.into_iter()).to_le_bytes().to_vec()
+}
+
+/// Resolve `cf` against `db`, shared by `RocksDb` and `RocksDbSnapshot` so
+/// both scope column-family access the same way: `None` means "use the
+/// default keyspace", and `Some(name)` must actually resolve on `db` or
+/// the call errors rather than quietly falling back to the default
+/// keyspace (which would defeat the isolation column families exist for).
+fn resolve_cf_handle<'a>(
+    db: &'a rocksdb::DB,
+    cf: &Option<String>,
+) -> Result<Option<&'a ColumnFamily>, RocksDbError> {
+    match cf {
+        None => Ok(None),
+        Some(name) => db.cf_handle(name).map(Some).ok_or_else(|| {
+            RocksDbError::from(format!(
+                "column family '{}' is not open on this handle",
+                name
+            ))
+        }),
+    }
+}
+
+/// Structured errors from a `RocksDb`/`RocksDbSnapshot` operation.
+///
+/// This crate's rocksdb dependency (the tikv fork whose `Writable` trait
+/// this file uses) reports every fallible operation
+/// as a plain `String` rather than a typed error — there is no distinct
+/// `rocksdb::Error` to convert from. `From<String>` is that conversion:
+/// it classifies the message into `Corruption` when rocksdb's own
+/// "Corruption: ..." prefix is present, and `Io` otherwise.
 #[derive(Debug)]
-pub struct RocksDbError(pub String);
+pub enum RocksDbError {
+    /// An I/O or other rocksdb-reported failure.
+    Io(String),
+    /// The underlying store reported data corruption (rocksdb's message
+    /// was prefixed `"Corruption:"`).
+    Corruption(String),
+}
 
 impl From<String> for RocksDbError {
     fn from(err: String) -> RocksDbError {
-        RocksDbError(err)
+        if err.starts_with("Corruption:") {
+            RocksDbError::Corruption(err)
+        } else {
+            RocksDbError::Io(err)
+        }
     }
 }
 impl Display for RocksDbError {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(f, "RocksDb error: {}", self.0)
+        match self {
+            RocksDbError::Io(reason) => write!(f, "RocksDb error: {}", reason),
+            RocksDbError::Corruption(reason) => write!(f, "RocksDb corruption: {}", reason),
+        }
     }
 }
 impl error::Error for RocksDbError {
     fn description(&self) -> &str {
-        &self.0
+        match self {
+            RocksDbError::Io(reason) => reason,
+            RocksDbError::Corruption(reason) => reason,
+        }
     }
 
     fn cause(&self) -> Option<&error::Error> {
@@ -48,19 +127,351 @@ impl error::Error for RocksDbError {
     }
 }
 
+/// Tunable options for opening a `RocksDb`. Controls on-disk size and
+/// point-lookup speed independently of the column families a caller opens.
+pub struct RocksDbConfig {
+    /// Bits per key for the block-based table's bloom filter, used to
+    /// short-circuit the many negative `contains` lookups tries do.
+    pub bloom_filter_bits: i32,
+    /// Compression applied to the node store on disk.
+    pub compression: rocksdb::DBCompressionType,
+    /// WAL recovery mode used when reopening after an unclean shutdown.
+    pub wal_recovery_mode: rocksdb::DBRecoveryMode,
+    /// Fixed prefix length for the slice transform backing `prefix_iter`.
+    /// `None` leaves prefix seeking unconfigured (full-key bloom/iteration
+    /// only).
+    pub prefix_extractor_len: Option<usize>,
+}
+
+impl Default for RocksDbConfig {
+    fn default() -> Self {
+        RocksDbConfig {
+            bloom_filter_bits: 10,
+            compression: rocksdb::DBCompressionType::Lz4,
+            wal_recovery_mode: rocksdb::DBRecoveryMode::PointInTime,
+            prefix_extractor_len: None,
+        }
+    }
+}
+
 /// Handle to RocksDb
 pub struct RocksDb {
     db: Arc<rocksdb::DB>,
+    /// When set, all `DB` operations on this handle are scoped to this
+    /// column family instead of the default keyspace.
+    cf: Option<String>,
+    /// Node writes staged by `insert_batch`, applied atomically by `flush`.
+    pending: WriteBatch,
 }
 
 impl RocksDb {
     /// Create or open a database at the give path.  Will panic on error
     pub fn new(dir: &str) -> Self {
-        match RDB::open_default(dir) {
-            Ok(db) => RocksDb { db: Arc::new(db) },
-            Err(reason) => panic!(reason),
+        match RocksDb::with_options(dir, RocksDbConfig::default()) {
+            Ok(db) => db,
+            Err(reason) => panic!("{}", reason),
+        }
+    }
+
+    /// Create or open a database at `dir` with the given `RocksDbConfig`
+    /// applied to the block-based table, compression and WAL recovery
+    /// mode, returning a `Result` instead of panicking on a bad path.
+    pub fn with_options(dir: &str, config: RocksDbConfig) -> Result<Self, RocksDbError> {
+        // This fork has no unified `Options`: database-wide knobs live on
+        // `DBOptions`, while the block-based table, compression and prefix
+        // extractor are per column family and live on `ColumnFamilyOptions`.
+        let mut block_opts = rocksdb::BlockBasedOptions::default();
+        block_opts.set_bloom_filter(config.bloom_filter_bits, false);
+
+        let mut cf_opts = ColumnFamilyOptions::new();
+        cf_opts.set_block_based_table_factory(&block_opts);
+        cf_opts.set_compression_type(config.compression);
+        if let Some(len) = config.prefix_extractor_len {
+            cf_opts
+                .set_prefix_extractor("fixed_prefix", rocksdb::SliceTransform::create_fixed_prefix(len))
+                .map_err(RocksDbError::from)?;
+        }
+
+        let mut db_opts = DBOptions::new();
+        db_opts.create_if_missing(true);
+        db_opts.set_wal_recovery_mode(config.wal_recovery_mode);
+
+        let db = RDB::open_cf(&db_opts, dir, vec![ColumnFamilyDescriptor::new("default", cf_opts)])
+            .map_err(|r| RocksDbError::from(r))?;
+        Ok(RocksDb {
+            db: Arc::new(db),
+            cf: None,
+            pending: WriteBatch::default(),
+        })
+    }
+
+    /// Create or open a database at `dir` with the given named column
+    /// families, creating any that don't already exist. Will panic on error.
+    ///
+    /// Use `with_column_family` to get a handle scoped to one of them.
+    pub fn new_with_column_families(dir: &str, cf_names: &[&str]) -> Self {
+        let mut db_opts = DBOptions::new();
+        db_opts.create_if_missing(true);
+        db_opts.create_missing_column_families(true);
+        let cfds: Vec<ColumnFamilyDescriptor> = cf_names
+            .iter()
+            .map(|name| ColumnFamilyDescriptor::new(*name, ColumnFamilyOptions::new()))
+            .collect();
+        match RDB::open_cf(&db_opts, dir, cfds) {
+            Ok(db) => RocksDb {
+                db: Arc::new(db),
+                cf: None,
+                pending: WriteBatch::default(),
+            },
+            Err(reason) => panic!("{}", reason),
         }
     }
+
+    /// Return a handle to the same database scoped to column family `name`.
+    /// The returned handle shares the underlying `rocksdb::DB` but every
+    /// `get`/`insert`/`contains`/`remove` call on it reads and writes only
+    /// within `name`, so independent tries (e.g. accounts vs. storage) can
+    /// share one database file without colliding on node hashes.
+    pub fn with_column_family(&self, name: &str) -> Self {
+        RocksDb {
+            db: self.db.clone(),
+            cf: Some(name.to_string()),
+            pending: WriteBatch::default(),
+        }
+    }
+
+    /// Drop an entire column family's data, e.g. to discard a retired trie.
+    pub fn drop_column_family(&self, name: &str) -> Result<(), RocksDbError> {
+        self.db.drop_cf(name).map_err(|r| RocksDbError::from(r))
+    }
+
+    /// Resolve the column family this handle is scoped to, if any.
+    ///
+    /// Returns `Ok(None)` when this handle targets the default keyspace
+    /// (no `with_column_family` call in its ancestry). When it *is* scoped
+    /// to a name, that name must resolve on the underlying `DB` — a typo,
+    /// a handle built against a `DB` that never opened the family, or one
+    /// since dropped via `drop_column_family` all surface as an `Err`
+    /// here instead of silently falling through to the default keyspace,
+    /// which would let two independently-scoped tries collide.
+    fn cf_handle(&self) -> Result<Option<&ColumnFamily>, RocksDbError> {
+        resolve_cf_handle(&self.db, &self.cf)
+    }
+
+    /// Take a read-only snapshot of the database at the current sequence
+    /// number. The snapshot keeps seeing the data as it is now even as
+    /// other handles on the same database (see `with_column_family`) keep
+    /// writing, so a historical root captured before the snapshot was
+    /// taken can still be read back consistently via
+    /// `PatriciaTrie::from(&snapshot, codec, &old_root)`. The snapshot
+    /// borrows this handle, so this handle itself cannot be written to
+    /// while the snapshot is alive.
+    pub fn snapshot(&self) -> RocksDbSnapshot<'_> {
+        RocksDbSnapshot {
+            db: self.db.clone(),
+            snapshot: self.db.snapshot(),
+            cf: self.cf.clone(),
+        }
+    }
+
+    /// Stage a batch of key/value pairs without writing them to disk yet.
+    ///
+    /// `keys` and `values` must be the same length; pairs are matched
+    /// by index. Staged writes go to whatever column family this handle
+    /// is scoped to (see `with_column_family`), the same as `DB::insert`.
+    /// Call `flush` to apply everything staged so far in a single
+    /// `WriteBatch`, so a root's worth of nodes either all land or none do.
+    pub fn insert_batch(&mut self, keys: &[Vec<u8>], values: &[Vec<u8>]) -> Result<(), RocksDbError> {
+        if keys.len() != values.len() {
+            return Err(RocksDbError::from(String::from(
+                "insert_batch: keys and values must be the same length",
+            )));
+        }
+        match self.cf_handle()? {
+            Some(cf) => {
+                for (key, value) in keys.iter().zip(values.iter()) {
+                    self.pending
+                        .put_cf(cf, key, value)
+                        .map_err(|r| RocksDbError::from(r))?;
+                }
+            }
+            None => {
+                for (key, value) in keys.iter().zip(values.iter()) {
+                    self.pending
+                        .put(key, value)
+                        .map_err(|r| RocksDbError::from(r))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply everything staged by `insert_batch` in one atomic write and
+    /// clear the pending batch.
+    pub fn flush(&mut self) -> Result<(), RocksDbError> {
+        let batch = mem::replace(&mut self.pending, WriteBatch::default());
+        self.db.write(batch).map_err(|r| RocksDbError::from(r))
+    }
+
+    /// Create or open a database at `dir` with node garbage collection
+    /// enabled: a dedicated refcount column family backed by
+    /// `refcount_merge`. Use `commit_root` and `prune` to keep it in sync
+    /// as roots come and go. Will panic on error.
+    pub fn new_with_gc(dir: &str) -> Self {
+        let mut db_opts = DBOptions::new();
+        db_opts.create_if_missing(true);
+        db_opts.create_missing_column_families(true);
+
+        // The merge operator is a `ColumnFamilyOptions` property in this
+        // fork, not a database-wide one — binding it anywhere else would
+        // mean `merge_cf` on `REFCOUNT_CF` never folds and refcounts would
+        // silently stay wrong (last write wins instead of summing deltas).
+        let mut refcount_opts = ColumnFamilyOptions::new();
+        refcount_opts.set_merge_operator("refcount_merge", refcount_merge);
+
+        let cfds = vec![ColumnFamilyDescriptor::new(REFCOUNT_CF, refcount_opts)];
+        match RDB::open_cf(&db_opts, dir, cfds) {
+            Ok(db) => RocksDb {
+                db: Arc::new(db),
+                cf: None,
+                pending: WriteBatch::default(),
+            },
+            Err(reason) => panic!("{}", reason),
+        }
+    }
+
+    /// Iterate over every stored entry from the start of the keyspace (or
+    /// the column family this handle is scoped to). Useful for whole-trie
+    /// export/backup and integrity scans that reconcile the node set
+    /// against what's reachable from a root.
+    pub fn iter(&self) -> Result<RocksDbIter<'_>, RocksDbError> {
+        let mut iter = match self.cf_handle()? {
+            Some(cf) => self.db.iter_cf(cf),
+            None => self.db.iter(),
+        };
+        iter.seek(rocksdb::SeekKey::Start);
+        Ok(RocksDbIter { iter, prefix: None, done: false })
+    }
+
+    /// Iterate over every stored entry whose key starts with `prefix`.
+    /// Fastest when the database was opened with a `RocksDbConfig` whose
+    /// `prefix_extractor_len` covers `prefix`'s length.
+    pub fn prefix_iter(&self, prefix: &[u8]) -> Result<RocksDbIter<'_>, RocksDbError> {
+        let mut iter = match self.cf_handle()? {
+            Some(cf) => self.db.iter_cf(cf),
+            None => self.db.iter(),
+        };
+        iter.seek(rocksdb::SeekKey::Key(prefix));
+        Ok(RocksDbIter {
+            iter,
+            prefix: Some(prefix.to_vec()),
+            done: false,
+        })
+    }
+
+    fn refcount_cf(&self) -> Result<&ColumnFamily, RocksDbError> {
+        self.db.cf_handle(REFCOUNT_CF).ok_or_else(|| {
+            RocksDbError::from(String::from(
+                "refcount column family not open; use RocksDb::new_with_gc",
+            ))
+        })
+    }
+
+    /// Commit a new root: stage `+1` merges for every node newly
+    /// referenced by it and `-1` merges for every node no longer
+    /// reachable from any live root, then apply them atomically alongside
+    /// the node writes themselves (via the `insert_batch`/`flush` path) so
+    /// a node is never deleted while still referenced by a retained root.
+    pub fn commit_root(
+        &mut self,
+        new_node_keys: &[Vec<u8>],
+        new_node_values: &[Vec<u8>],
+        removed_node_keys: &[Vec<u8>],
+    ) -> Result<(), RocksDbError> {
+        self.insert_batch(new_node_keys, new_node_values)?;
+        let cf = self.refcount_cf()?;
+        for key in new_node_keys {
+            self.pending
+                .merge_cf(cf, key, &1i64.to_le_bytes())
+                .map_err(|r| RocksDbError::from(r))?;
+        }
+        for key in removed_node_keys {
+            self.pending
+                .merge_cf(cf, key, &(-1i64).to_le_bytes())
+                .map_err(|r| RocksDbError::from(r))?;
+        }
+        self.flush()
+    }
+
+    /// Scan the refcount column family and delete every node whose merged
+    /// count has reached zero, skipping any key still present in
+    /// `live_roots` so a retained root is never reclaimed out from under
+    /// itself. Returns the number of nodes reclaimed.
+    pub fn prune(&mut self, live_roots: &HashSet<Vec<u8>>) -> Result<usize, RocksDbError> {
+        let refcount_cf = self.refcount_cf()?;
+        let mut iter = self.db.iter_cf(refcount_cf);
+        iter.seek(rocksdb::SeekKey::Start);
+
+        let mut batch = WriteBatch::default();
+        let mut reclaimed = 0;
+        while iter.valid() {
+            let key = iter.key().to_vec();
+            let value = iter.value();
+            if !live_roots.contains(&key) {
+                // A refcount entry must be an 8-byte little-endian `i64` —
+                // anything else is a corrupt or foreign entry, not proof
+                // the node is unreferenced, so it must never be coerced to
+                // zero and reclaimed.
+                let count: i64 = i64::from_le_bytes(value.try_into().map_err(|_| {
+                    RocksDbError::from(format!(
+                        "refcount entry for key {:?} is not an 8-byte count",
+                        key
+                    ))
+                })?);
+                if count <= 0 {
+                    batch.delete(&key).map_err(|r| RocksDbError::from(r))?;
+                    batch
+                        .delete_cf(refcount_cf, &key)
+                        .map_err(|r| RocksDbError::from(r))?;
+                    reclaimed += 1;
+                }
+            }
+            iter.next();
+        }
+        self.db.write(batch).map_err(|r| RocksDbError::from(r))?;
+        Ok(reclaimed)
+    }
+}
+
+/// Adapts rocksdb's manual cursor-style `DBIterator` (`seek`/`valid`/`key`/
+/// `value`/`next`) into a standard Rust `Iterator` yielding owned key/value
+/// pairs, as produced by `RocksDb::iter`/`prefix_iter`.
+pub struct RocksDbIter<'a> {
+    iter: rocksdb::DBIterator<'a>,
+    prefix: Option<Vec<u8>>,
+    done: bool,
+}
+
+impl<'a> Iterator for RocksDbIter<'a> {
+    type Item = (Box<[u8]>, Box<[u8]>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || !self.iter.valid() {
+            return None;
+        }
+        if let Some(ref prefix) = self.prefix {
+            if !self.iter.key().starts_with(prefix.as_slice()) {
+                self.done = true;
+                return None;
+            }
+        }
+        let item = (
+            self.iter.key().to_vec().into_boxed_slice(),
+            self.iter.value().to_vec().into_boxed_slice(),
+        );
+        self.iter.next();
+        Some(item)
+    }
 }
 
 // Implemented to satisfy the DB Trait
@@ -74,16 +485,24 @@ impl DB for RocksDb {
     type Error = RocksDbError;
     /// Get a value from the database.
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
-        match self.db.get(key) {
+        let result = match self.cf_handle()? {
+            Some(cf) => self.db.get_cf(cf, key),
+            None => self.db.get(key),
+        };
+        match result {
             Ok(Some(val)) => Ok(Some(val.to_owned())),
+            Ok(None) => Ok(None),
             Err(reason) => Err(RocksDbError::from(reason)),
-            Ok(None) => Err(RocksDbError::from(String::from("Key not found"))),
         }
     }
 
     /// Insert a key value
     fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Self::Error> {
-        self.db.put(key, value).map_err(|r| RocksDbError::from(r))
+        match self.cf_handle()? {
+            Some(cf) => self.db.put_cf(cf, key, value),
+            None => self.db.put(key, value),
+        }
+        .map_err(|r| RocksDbError::from(r))
     }
 
     /// Check if a key is in the database
@@ -96,15 +515,228 @@ impl DB for RocksDb {
 
     /// Remove a key/value pair
     fn remove(&mut self, key: &[u8]) -> Result<(), Self::Error> {
-        self.db.delete(key).map_err(|r| RocksDbError::from(r))
+        match self.cf_handle()? {
+            Some(cf) => self.db.delete_cf(cf, key),
+            None => self.db.delete(key),
+        }
+        .map_err(|r| RocksDbError::from(r))
+    }
+}
+
+/// A frozen, read-only view of a `RocksDb` pinned to the sequence number
+/// at the time `RocksDb::snapshot` was taken. Writes made to the database
+/// through other handles after that point are invisible here, which lets
+/// a caller reconstruct and query a historical root while new roots keep
+/// being committed concurrently.
+pub struct RocksDbSnapshot<'a> {
+    /// Handle to the owning database, used only to resolve column family
+    /// names: `rocksdb::Snapshot` itself has no `cf_handle`, that's
+    /// resolved off the `DB` it was taken from.
+    db: Arc<rocksdb::DB>,
+    snapshot: rocksdb::Snapshot<'a>,
+    cf: Option<String>,
+}
+
+impl<'a> fmt::Debug for RocksDbSnapshot<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rocksdb trie snapshot")
+    }
+}
+
+impl<'a> DB for RocksDbSnapshot<'a> {
+    type Error = RocksDbError;
+
+    /// Get a value as it stood when the snapshot was taken.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let result = match resolve_cf_handle(&self.db, &self.cf)? {
+            Some(cf) => self.snapshot.get_cf(cf, key),
+            None => self.snapshot.get(key),
+        };
+        match result {
+            Ok(Some(val)) => Ok(Some(val.to_owned())),
+            Ok(None) => Ok(None),
+            Err(reason) => Err(RocksDbError::from(reason)),
+        }
+    }
+
+    /// Check if a key was present when the snapshot was taken.
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        if let Ok(Some(_)) = self.get(key) {
+            return Ok(true);
+        }
+        return Ok(false);
+    }
+
+    /// A snapshot is read-only; it has nothing to write through to.
+    fn insert(&mut self, _key: &[u8], _value: &[u8]) -> Result<(), Self::Error> {
+        Err(RocksDbError::from(String::from(
+            "RocksDbSnapshot is read-only",
+        )))
+    }
+
+    /// A snapshot is read-only; it has nothing to write through to.
+    fn remove(&mut self, _key: &[u8]) -> Result<(), Self::Error> {
+        Err(RocksDbError::from(String::from(
+            "RocksDbSnapshot is read-only",
+        )))
+    }
+}
+
+/// A minimal bounded LRU map, used by `CachedDb` instead of pulling in a
+/// new crate dependency. Recency is tracked with a `VecDeque` of keys
+/// alongside a `HashMap` for storage; eviction pops the front of the
+/// queue. A `capacity` of zero disables caching outright rather than
+/// panicking: `put` becomes a no-op and every `get` is a miss.
+struct LruCache<K, V> {
+    capacity: usize,
+    map: HashMap<K, V>,
+    order: VecDeque<K>,
+}
+
+impl<K: Eq + Hash + Clone, V> LruCache<K, V> {
+    fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            map: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn touch(&mut self, key: &K) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            let key = self.order.remove(pos).unwrap();
+            self.order.push_back(key);
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<&V> {
+        if self.map.contains_key(key) {
+            self.touch(key);
+        }
+        self.map.get(key)
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    fn put(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.map.contains_key(&key) {
+            self.touch(&key);
+            self.map.insert(key, value);
+            return;
+        }
+        if self.map.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.map.remove(&oldest);
+            }
+        }
+        self.order.push_back(key.clone());
+        self.map.insert(key, value);
+    }
+
+    fn pop(&mut self, key: &K) {
+        if self.map.remove(key).is_some() {
+            if let Some(pos) = self.order.iter().position(|k| k == key) {
+                self.order.remove(pos);
+            }
+        }
+    }
+}
+
+/// A bounded in-memory LRU cache wrapped around any `DB`. Trie traversal
+/// re-reads the same branch/extension nodes over and over, so `get` serves
+/// hits straight from memory and only falls through to `inner` on a miss;
+/// `insert`/`remove` write through to `inner` and keep the cached entry in
+/// sync. Doesn't change the `DB` trait contract, so it drops in anywhere a
+/// `RocksDb` (or any other `DB` impl) is used today.
+pub struct CachedDb<D: DB> {
+    inner: D,
+    cache: RefCell<LruCache<Vec<u8>, Vec<u8>>>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+
+impl<D: DB> CachedDb<D> {
+    /// Wrap `inner`, caching up to `capacity` decoded node entries. A
+    /// `capacity` of zero is valid and simply disables caching.
+    pub fn new(inner: D, capacity: usize) -> Self {
+        CachedDb {
+            inner,
+            cache: RefCell::new(LruCache::new(capacity)),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+        }
+    }
+
+    /// Number of `get` calls served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits.get()
+    }
+
+    /// Number of `get` calls that had to fall through to `inner`.
+    pub fn misses(&self) -> u64 {
+        self.misses.get()
+    }
+}
+
+impl<D: DB> fmt::Debug for CachedDb<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cached db store")
+    }
+}
+
+impl<D: DB> DB for CachedDb<D> {
+    type Error = D::Error;
+
+    /// Serve a hit from the cache, otherwise read through to `inner` and
+    /// cache the result.
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(value) = self.cache.borrow_mut().get(&key.to_vec()) {
+            self.hits.set(self.hits.get() + 1);
+            return Ok(Some(value.clone()));
+        }
+        self.misses.set(self.misses.get() + 1);
+        let value = self.inner.get(key)?;
+        if let Some(ref value) = value {
+            self.cache.borrow_mut().put(key.to_vec(), value.clone());
+        }
+        Ok(value)
+    }
+
+    /// Write through to `inner` and refresh the cached entry.
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<(), Self::Error> {
+        self.inner.insert(key, value)?;
+        self.cache.borrow_mut().put(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    /// Check the cache first, otherwise fall through to `inner`.
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        if self.cache.borrow_mut().contains(&key.to_vec()) {
+            return Ok(true);
+        }
+        self.inner.contains(key)
+    }
+
+    /// Write through to `inner` and evict the cached entry.
+    fn remove(&mut self, key: &[u8]) -> Result<(), Self::Error> {
+        self.inner.remove(key)?;
+        self.cache.borrow_mut().pop(&key.to_vec());
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::RocksDb;
+    use super::{fold_refcount_deltas, CachedDb, RocksDb, RocksDbError, REFCOUNT_CF};
     use crate::cita_trie::codec::RLPNodeCodec;
+    use crate::cita_trie::db::DB;
     use crate::cita_trie::trie::{PatriciaTrie, Trie};
+    use std::convert::TryInto;
     use std::fs;
 
     #[test]
@@ -137,4 +769,213 @@ mod tests {
 
         let _ = fs::remove_dir_all(test_dir);
     }
+
+    #[test]
+    fn test_insert_batch_respects_column_family() {
+        let test_dir = "data_insert_batch_cf";
+        let mut db = RocksDb::new_with_column_families(test_dir, &["accounts"]);
+        let mut accounts = db.with_column_family("accounts");
+
+        accounts
+            .insert_batch(
+                &[b"node-a".to_vec(), b"node-b".to_vec()],
+                &[b"value-a".to_vec(), b"value-b".to_vec()],
+            )
+            .unwrap();
+        accounts.flush().unwrap();
+
+        assert_eq!(
+            accounts.get(b"node-a").unwrap(),
+            Some(b"value-a".to_vec())
+        );
+        // The batch must not have leaked into the default keyspace.
+        assert_eq!(db.get(b"node-a").unwrap(), None);
+
+        let _ = fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_unresolved_column_family_errors_instead_of_silently_using_default() {
+        let test_dir = "data_unresolved_cf";
+        // Opens only the default keyspace; "does-not-exist" is never created.
+        let db = RocksDb::new(test_dir);
+        let mut scoped = db.with_column_family("does-not-exist");
+
+        match scoped.get(b"key") {
+            Err(RocksDbError::Io(_)) => {}
+            other => panic!(
+                "expected an error for an unresolved column family, got {:?}",
+                other
+            ),
+        }
+        match scoped.insert(b"key", b"value") {
+            Err(RocksDbError::Io(_)) => {}
+            other => panic!(
+                "expected an error for an unresolved column family, got {:?}",
+                other
+            ),
+        }
+        // And critically: the write above must not have leaked into the
+        // default keyspace despite the scoped handle failing to resolve.
+        assert_eq!(db.get(b"key").unwrap(), None);
+
+        let _ = fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_snapshot_isolation() {
+        let test_dir = "data_snapshot_isolation";
+        let mut db = RocksDb::new(test_dir);
+        db.insert(b"key", b"old-value").unwrap();
+
+        let snap = db.snapshot();
+        assert_eq!(snap.get(b"key").unwrap(), Some(b"old-value".to_vec()));
+
+        db.insert(b"key", b"new-value").unwrap();
+
+        // The snapshot keeps reporting the value as of when it was taken...
+        assert_eq!(snap.get(b"key").unwrap(), Some(b"old-value".to_vec()));
+        // ...while the live handle sees the new write.
+        assert_eq!(db.get(b"key").unwrap(), Some(b"new-value".to_vec()));
+
+        drop(snap);
+        let _ = fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_snapshot_unresolved_column_family_errors() {
+        let test_dir = "data_snapshot_unresolved_cf";
+        let db = RocksDb::new(test_dir);
+        let scoped = db.with_column_family("does-not-exist");
+        let snap = scoped.snapshot();
+
+        match snap.get(b"key") {
+            Err(RocksDbError::Io(_)) => {}
+            other => panic!(
+                "expected an error for an unresolved column family, got {:?}",
+                other
+            ),
+        }
+
+        drop(snap);
+        let _ = fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_refcount_merge_folds_deltas() {
+        let plus_one = 1i64.to_le_bytes();
+        let minus_one = (-1i64).to_le_bytes();
+
+        // No existing count: two +1s and a -1 fold to 1.
+        let deltas = vec![plus_one.as_slice(), plus_one.as_slice(), minus_one.as_slice()];
+        assert_eq!(fold_refcount_deltas(None, deltas.into_iter()), 1);
+
+        // Existing count of 2, two -1 merges bring it to 0 (ready to prune).
+        let existing = 2i64.to_le_bytes();
+        let deltas = vec![minus_one.as_slice(), minus_one.as_slice()];
+        assert_eq!(fold_refcount_deltas(Some(&existing), deltas.into_iter()), 0);
+    }
+
+    #[test]
+    fn test_new_with_gc_registers_merge_operator_on_refcount_cf() {
+        // Unlike `test_refcount_merge_folds_deltas`, which only exercises
+        // the pure `fold_refcount_deltas` helper, this reads a count back
+        // through the DB to prove `refcount_merge` is actually registered
+        // and folding on `REFCOUNT_CF`, not silently last-write-wins.
+        let test_dir = "data_gc_merge_registered";
+        let mut db = RocksDb::new_with_gc(test_dir);
+        let key = b"node-1".to_vec();
+
+        db.commit_root(&[key.clone()], &[b"value-1".to_vec()], &[])
+            .unwrap();
+        db.commit_root(&[key.clone()], &[b"value-1".to_vec()], &[])
+            .unwrap();
+
+        let refcounts = db.with_column_family(REFCOUNT_CF);
+        let raw = refcounts
+            .get(&key)
+            .unwrap()
+            .expect("refcount entry must exist after two commits");
+        let count = i64::from_le_bytes(raw.as_slice().try_into().unwrap());
+        assert_eq!(count, 2);
+
+        let _ = fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_prune_errors_on_unparseable_refcount_entry() {
+        use std::collections::HashSet;
+
+        let test_dir = "data_prune_corrupt_refcount";
+        let mut db = RocksDb::new_with_gc(test_dir);
+        let key = b"node-1".to_vec();
+
+        // Write a refcount entry directly, bypassing the merge operator,
+        // so it's the wrong width instead of a valid 8-byte i64.
+        let mut refcounts = db.with_column_family(REFCOUNT_CF);
+        refcounts.insert(&key, b"not-a-count").unwrap();
+
+        let result = db.prune(&HashSet::new());
+        assert!(
+            result.is_err(),
+            "a malformed refcount entry must not be silently coerced to zero and reclaimed"
+        );
+
+        let _ = fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_cached_db_hit_miss_counters() {
+        let test_dir = "data_cached_db";
+        let mut cached = CachedDb::new(RocksDb::new(test_dir), 10);
+
+        cached.insert(b"key", b"value").unwrap();
+        assert_eq!(cached.hits(), 0);
+        assert_eq!(cached.misses(), 0);
+
+        // `insert` primed the cache, so this `get` is served from memory.
+        assert_eq!(cached.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(cached.hits(), 1);
+        assert_eq!(cached.misses(), 0);
+
+        // A key that was never cached falls through to `inner` as a miss.
+        assert_eq!(cached.get(b"other").unwrap(), None);
+        assert_eq!(cached.hits(), 1);
+        assert_eq!(cached.misses(), 1);
+
+        let _ = fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_iter_and_prefix_iter() {
+        let test_dir = "data_iter";
+        let mut db = RocksDb::new(test_dir);
+        db.insert(b"node-1", b"value-1").unwrap();
+        db.insert(b"node-2", b"value-2").unwrap();
+        db.insert(b"other", b"value-3").unwrap();
+
+        let mut all: Vec<_> = db.iter().unwrap().collect();
+        all.sort();
+        assert_eq!(all.len(), 3);
+
+        let prefixed: Vec<_> = db.prefix_iter(b"node-").unwrap().collect();
+        assert_eq!(prefixed.len(), 2);
+        for (key, _) in &prefixed {
+            assert!(key.starts_with(b"node-"));
+        }
+
+        let _ = fs::remove_dir_all(test_dir);
+    }
+
+    #[test]
+    fn test_rocksdb_error_classifies_corruption_vs_io() {
+        match RocksDbError::from(String::from("Corruption: block checksum mismatch")) {
+            RocksDbError::Corruption(_) => {}
+            other => panic!("expected Corruption, got {:?}", other),
+        }
+        match RocksDbError::from(String::from("IO error: No such file or directory")) {
+            RocksDbError::Io(_) => {}
+            other => panic!("expected Io, got {:?}", other),
+        }
+    }
 }